@@ -1,48 +1,204 @@
 use crate::VK_NULL_HANDLE;
+use std::alloc::{self, Layout};
 use std::{borrow, fmt, ops};
-#[cfg(feature = "nightly")]
-use std::{
-    collections::HashMap,
-    hash::BuildHasherDefault,
-    sync::{Arc, Mutex},
-};
+#[cfg(feature = "leak-detection")]
+use std::{collections::HashMap, hash::BuildHasherDefault, sync::Mutex};
 
-use copyless::{BoxAllocation, BoxHelper};
-
-#[cfg(feature = "nightly")]
+#[cfg(feature = "leak-detection")]
 use lazy_static::lazy_static;
 
-#[cfg(feature = "nightly")]
+#[cfg(feature = "leak-detection")]
+type RegistryShard = Mutex<HashMap<usize, &'static str, BuildHasherDefault<fxhash::FxHasher>>>;
+
+// Number of registry shards; a power of two so the shard index is a cheap mask.
+#[cfg(feature = "leak-detection")]
+const SHARD_COUNT: usize = 64;
+
+// Handle payloads are at least pointer-aligned, so the low bits of their
+// address carry no entropy — shift them out before masking down to a shard.
+#[cfg(feature = "leak-detection")]
+const SHARD_SHIFT: u32 = std::mem::align_of::<usize>().trailing_zeros();
+
+#[cfg(feature = "leak-detection")]
 lazy_static! {
-    static ref REGISTRY: Arc<Mutex<HashMap<usize, &'static str, BuildHasherDefault<fxhash::FxHasher>>>> =
-        Arc::new(Mutex::new(HashMap::default()));
+    static ref REGISTRY: [RegistryShard; SHARD_COUNT] =
+        std::array::from_fn(|_| Mutex::new(HashMap::default()));
+}
+
+// Pick the shard owning a given handle address.
+#[cfg(feature = "leak-detection")]
+#[inline]
+fn shard_of(ptr: usize) -> &'static RegistryShard {
+    &REGISTRY[(ptr >> SHARD_SHIFT) & (SHARD_COUNT - 1)]
+}
+
+/// Memory source for handle payloads.
+///
+/// Mirrors the split in `VkAllocationCallbacks`: `allocate`/`deallocate` hand
+/// back raw blocks for a given `Layout`, so a handle can round-trip its storage
+/// through whichever source created it — the global allocator by default, or an
+/// application-supplied callback table.
+pub trait Allocator {
+    fn allocate(&self, layout: Layout) -> *mut u8;
+    fn deallocate(&self, ptr: *mut u8, layout: Layout);
+    // Whether blocks come from the global allocator. Handles created from a
+    // non-system source are tracked in debug builds (see `origin`) so releasing
+    // one through the wrong `unbox`/`unbox_in` is caught instead of corrupting
+    // the heap silently.
+    fn is_system(&self) -> bool {
+        true
+    }
+}
+
+// Debug-only guard that records which handles were allocated from a non-system
+// (callback) source, so `unbox`/`unbox_in` can assert a handle is released
+// through a matching allocator. Sharded the same way as `REGISTRY` so it does
+// not reintroduce the global-lock contention removed in the registry rework;
+// the common `SystemAllocator` path records nothing on allocation, while every
+// release still takes one shard lock to check the handle's origin.
+#[cfg(debug_assertions)]
+mod origin {
+    use lazy_static::lazy_static;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    const SHARD_COUNT: usize = 64;
+    const SHARD_SHIFT: u32 = std::mem::align_of::<usize>().trailing_zeros();
+
+    lazy_static! {
+        static ref FOREIGN: [Mutex<HashSet<usize>>; SHARD_COUNT] =
+            std::array::from_fn(|_| Mutex::new(HashSet::new()));
+    }
+
+    fn shard(ptr: usize) -> &'static Mutex<HashSet<usize>> {
+        &FOREIGN[(ptr >> SHARD_SHIFT) & (SHARD_COUNT - 1)]
+    }
+
+    pub fn record_foreign(ptr: usize) {
+        shard(ptr).lock().unwrap().insert(ptr);
+    }
+
+    // Removes and reports whether `ptr` was allocated from a foreign source.
+    pub fn take_foreign(ptr: usize) -> bool {
+        shard(ptr).lock().unwrap().remove(&ptr)
+    }
+}
+
+/// The default [`Allocator`], backed by the global Rust allocator.
+pub struct SystemAllocator;
+
+impl Allocator for SystemAllocator {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc(layout) }
+    }
+    fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { alloc::dealloc(ptr, layout) };
+    }
+}
+
+/// An [`Allocator`] forwarding to application-supplied `VkAllocationCallbacks`.
+///
+/// Every block is tagged with the `VkSystemAllocationScope` the owning object
+/// was created at. The table is honored as a whole: allocation and free must
+/// come from the same source, so we forward to the callbacks only when both
+/// `pfnAllocation` and `pfnFree` are present and otherwise fall back wholesale
+/// to the global allocator. Honoring one but not the other would mismatch the
+/// allocation and deallocation paths and corrupt the heap.
+pub struct CallbackAllocator {
+    callbacks: crate::VkAllocationCallbacks,
+    scope: crate::VkSystemAllocationScope,
+}
+
+impl CallbackAllocator {
+    pub fn new(
+        callbacks: crate::VkAllocationCallbacks,
+        scope: crate::VkSystemAllocationScope,
+    ) -> Self {
+        CallbackAllocator { callbacks, scope }
+    }
+
+    // Whether the supplied table provides a matched allocate/free pair we can
+    // route through; both operations check the same table, so they agree on
+    // the source.
+    fn honors_callbacks(&self) -> bool {
+        self.callbacks.pfnAllocation.is_some() && self.callbacks.pfnFree.is_some()
+    }
+}
+
+impl Allocator for CallbackAllocator {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        if self.honors_callbacks() {
+            let pfn = self.callbacks.pfnAllocation.unwrap();
+            unsafe {
+                pfn(
+                    self.callbacks.pUserData,
+                    layout.size(),
+                    layout.align(),
+                    self.scope,
+                ) as *mut u8
+            }
+        } else {
+            unsafe { alloc::alloc(layout) }
+        }
+    }
+    fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        if self.honors_callbacks() {
+            let pfn = self.callbacks.pfnFree.unwrap();
+            unsafe { pfn(self.callbacks.pUserData, ptr as *mut _) };
+        } else {
+            unsafe { alloc::dealloc(ptr, layout) };
+        }
+    }
+    fn is_system(&self) -> bool {
+        !self.honors_callbacks()
+    }
 }
 
+/// Zero-size error returned by the fallible `try_*` allocation paths, mapped by
+/// callers to `VK_ERROR_OUT_OF_HOST_MEMORY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocError;
+
 #[repr(C)]
 pub struct Handle<T>(*mut T);
 
-#[cfg(feature = "nightly")]
+#[cfg(feature = "leak-detection")]
 impl Handle<()> {
     pub fn report_leaks() {
         println!("Leaked handles:");
-        let mut map = REGISTRY.lock().unwrap();
-        for (_, type_id) in map.drain() {
-            println!("\t{:?}", type_id);
+        let mut by_type: HashMap<&'static str, Vec<usize>> = HashMap::new();
+        for shard in REGISTRY.iter() {
+            for (ptr, name) in shard.lock().unwrap().drain() {
+                by_type.entry(name).or_default().push(ptr);
+            }
+        }
+        for (name, mut addrs) in by_type {
+            addrs.sort_unstable();
+            let addrs = addrs
+                .iter()
+                .map(|addr| format!("{:#x}", addr))
+                .collect::<Vec<_>>();
+            println!(
+                "\t{} \u{00d7} Handle<{}> at [{}]",
+                addrs.len(),
+                name,
+                addrs.join(", ")
+            );
         }
     }
 }
 
-pub struct HandleAllocation<T>(BoxAllocation<T>);
+pub struct HandleAllocation<T>(*mut T);
 
 impl<T> HandleAllocation<T> {
     #[inline(always)]
     pub fn init(self, value: T) -> Handle<T> {
-        let ptr = Box::into_raw(self.0.init(value));
-        #[cfg(feature = "nightly")]
+        let ptr = self.0;
+        unsafe { ptr.write(value) };
+        #[cfg(feature = "leak-detection")]
         {
-            use std::intrinsics::type_name;
-            let name = type_name::<T>();
-            REGISTRY.lock().unwrap().insert(ptr as _, name);
+            let name = std::any::type_name::<T>();
+            shard_of(ptr as usize).lock().unwrap().insert(ptr as _, name);
         }
         Handle(ptr)
     }
@@ -50,7 +206,46 @@ impl<T> HandleAllocation<T> {
 
 impl<T: 'static> Handle<T> {
     pub fn alloc() -> HandleAllocation<T> {
-        HandleAllocation(Box::alloc())
+        Self::alloc_in(&SystemAllocator)
+    }
+
+    // Invariant: a handle allocated through `alloc_in`/`try_alloc_in` must be
+    // released through `unbox_in` with an equivalent allocator. The `#[repr(C)]`
+    // handle is a bare pointer and cannot record its source, so releasing a
+    // `CallbackAllocator` handle through the plain `unbox` path would free
+    // callback memory through the global allocator.
+    pub fn alloc_in<A: Allocator>(allocator: &A) -> HandleAllocation<T> {
+        let layout = Layout::new::<T>();
+        let ptr = allocator.allocate(layout) as *mut T;
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        #[cfg(debug_assertions)]
+        if !allocator.is_system() {
+            origin::record_foreign(ptr as usize);
+        }
+        HandleAllocation(ptr)
+    }
+
+    // Fallible counterpart to `alloc`: returns `Err` on allocation failure so
+    // the ICD entry points can surface `VK_ERROR_OUT_OF_HOST_MEMORY` instead of
+    // aborting the process.
+    pub fn try_alloc() -> Result<HandleAllocation<T>, AllocError> {
+        Self::try_alloc_in(&SystemAllocator)
+    }
+
+    pub fn try_alloc_in<A: Allocator>(allocator: &A) -> Result<HandleAllocation<T>, AllocError> {
+        let layout = Layout::new::<T>();
+        let ptr = allocator.allocate(layout) as *mut T;
+        if ptr.is_null() {
+            Err(AllocError)
+        } else {
+            #[cfg(debug_assertions)]
+            if !allocator.is_system() {
+                origin::record_foreign(ptr as usize);
+            }
+            Ok(HandleAllocation(ptr))
+        }
     }
 
     // Note: ideally this constructor isn't used
@@ -58,19 +253,41 @@ impl<T: 'static> Handle<T> {
         Self::alloc().init(value)
     }
 
+    pub fn try_new(value: T) -> Result<Self, AllocError> {
+        Ok(Self::try_alloc()?.init(value))
+    }
+
     pub fn null() -> Self {
         Handle(VK_NULL_HANDLE as *mut _)
     }
 
     pub fn unbox(self) -> Option<T> {
+        self.unbox_in(&SystemAllocator)
+    }
+
+    // `allocator` must be equivalent to the one passed to `alloc_in`/
+    // `try_alloc_in` for this handle; see the invariant on `alloc_in`.
+    pub fn unbox_in<A: Allocator>(self, allocator: &A) -> Option<T> {
         if self.0 == VK_NULL_HANDLE as *mut T {
             None
         } else {
-            #[cfg(feature = "nightly")]
+            #[cfg(feature = "leak-detection")]
             {
-                REGISTRY.lock().unwrap().remove(&(self.0 as _)).unwrap();
+                shard_of(self.0 as usize)
+                    .lock()
+                    .unwrap()
+                    .remove(&(self.0 as _))
+                    .unwrap();
             }
-            Some(*unsafe { Box::from_raw(self.0) })
+            #[cfg(debug_assertions)]
+            debug_assert_eq!(
+                origin::take_foreign(self.0 as usize),
+                !allocator.is_system(),
+                "handle released through a different allocator than it was created with"
+            );
+            let value = unsafe { self.0.read() };
+            allocator.deallocate(self.0 as *mut u8, Layout::new::<T>());
+            Some(value)
         }
     }
 
@@ -84,12 +301,15 @@ impl<T: 'static> Handle<T> {
 }
 
 impl<T> Handle<T> {
-    #[cfg(feature = "nightly")]
+    #[cfg(feature = "leak-detection")]
     #[inline]
     fn check(&self) {
-        assert!(REGISTRY.lock().unwrap().contains_key(&(self.0 as _)));
+        assert!(shard_of(self.0 as usize)
+            .lock()
+            .unwrap()
+            .contains_key(&(self.0 as _)));
     }
-    #[cfg(not(feature = "nightly"))]
+    #[cfg(not(feature = "leak-detection"))]
     #[inline]
     fn check(&self) {
         debug_assert!(!self.0.is_null());
@@ -145,8 +365,9 @@ pub type DispatchHandle<T> = Handle<T>;
 
 #[cfg(feature = "dispatch")]
 mod dispatch {
+    use super::{AllocError, Allocator, SystemAllocator};
     use crate::VK_NULL_HANDLE;
-    use copyless::{BoxAllocation, BoxHelper};
+    use std::alloc::{self, Layout};
     use std::{borrow, fmt, ops};
 
     const ICD_LOADER_MAGIC: u64 = 0x01CDC0DE;
@@ -154,34 +375,89 @@ mod dispatch {
     #[repr(C)]
     pub struct DispatchHandle<T>(*mut (u64, T));
 
-    pub struct DisplatchHandleAllocation<T>(BoxAllocation<(u64, T)>);
+    pub struct DisplatchHandleAllocation<T>(*mut (u64, T));
 
     impl<T> DisplatchHandleAllocation<T> {
         #[inline(always)]
         pub fn init(self, value: T) -> DispatchHandle<T> {
-            let ptr = Box::into_raw(self.0.init((ICD_LOADER_MAGIC, value)));
+            let ptr = self.0;
+            unsafe { ptr.write((ICD_LOADER_MAGIC, value)) };
             DispatchHandle(ptr)
         }
     }
 
     impl<T> DispatchHandle<T> {
         pub fn alloc() -> DisplatchHandleAllocation<T> {
-            DisplatchHandleAllocation(Box::alloc())
+            Self::alloc_in(&SystemAllocator)
+        }
+
+        // Same round-trip invariant as `Handle::alloc_in`: release through
+        // `unbox_in` with an equivalent allocator.
+        pub fn alloc_in<A: Allocator>(allocator: &A) -> DisplatchHandleAllocation<T> {
+            let layout = Layout::new::<(u64, T)>();
+            let ptr = allocator.allocate(layout) as *mut (u64, T);
+            if ptr.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            #[cfg(debug_assertions)]
+            if !allocator.is_system() {
+                super::origin::record_foreign(ptr as usize);
+            }
+            DisplatchHandleAllocation(ptr)
+        }
+
+        // Fallible counterpart to `alloc`, mirroring `Handle::try_alloc`.
+        pub fn try_alloc() -> Result<DisplatchHandleAllocation<T>, AllocError> {
+            Self::try_alloc_in(&SystemAllocator)
+        }
+
+        pub fn try_alloc_in<A: Allocator>(
+            allocator: &A,
+        ) -> Result<DisplatchHandleAllocation<T>, AllocError> {
+            let layout = Layout::new::<(u64, T)>();
+            let ptr = allocator.allocate(layout) as *mut (u64, T);
+            if ptr.is_null() {
+                Err(AllocError)
+            } else {
+                #[cfg(debug_assertions)]
+                if !allocator.is_system() {
+                    super::origin::record_foreign(ptr as usize);
+                }
+                Ok(DisplatchHandleAllocation(ptr))
+            }
         }
 
         pub fn new(value: T) -> Self {
             Self::alloc().init(value)
         }
 
+        pub fn try_new(value: T) -> Result<Self, AllocError> {
+            Ok(Self::try_alloc()?.init(value))
+        }
+
         pub fn null() -> Self {
             DispatchHandle(VK_NULL_HANDLE as *mut _)
         }
 
         pub fn unbox(self) -> Option<T> {
+            self.unbox_in(&SystemAllocator)
+        }
+
+        // `allocator` must match the one passed to `alloc_in`/`try_alloc_in`
+        // for this handle; see the invariant on `alloc_in`.
+        pub fn unbox_in<A: Allocator>(self, allocator: &A) -> Option<T> {
             if self.0 == VK_NULL_HANDLE as *mut (u64, T) {
                 None
             } else {
-                Some(unsafe { Box::from_raw(self.0) }.1)
+                #[cfg(debug_assertions)]
+                debug_assert_eq!(
+                    super::origin::take_foreign(self.0 as usize),
+                    !allocator.is_system(),
+                    "handle released through a different allocator than it was created with"
+                );
+                let (_, value) = unsafe { self.0.read() };
+                allocator.deallocate(self.0 as *mut u8, Layout::new::<(u64, T)>());
+                Some(value)
             }
         }
 